@@ -0,0 +1,231 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display};
+
+use crate::{
+    conversion::{trait_impl_generator, trait_impl_generator::TraitImplGlue},
+    type_database::{TypeDatabase, VirtualMethod},
+    types::{Namespace, TypeName},
+};
+
+use super::type_converter::TypeConverter;
+
+/// Errors which can occur while converting a parsed C++ type or function
+/// signature into its Rust/cxx-bridge equivalent.
+#[derive(Debug)]
+pub(crate) enum ConvertError {
+    /// An otherwise-unqualified type reference couldn't be resolved to any
+    /// known type, in the current namespace, an enclosing one, or anywhere
+    /// else we've seen a type declared.
+    UnknownType(String),
+    /// An otherwise-unqualified type reference matched more than one known
+    /// type in different namespaces, and there was no alias or enclosing-
+    /// namespace match to prefer one over the others.
+    AmbiguousType(String),
+    /// A typedef chain refers back to itself, directly or through
+    /// intermediate typedefs (optionally via pointer/reference
+    /// indirection), so it has no well-defined underlying type.
+    RecursiveTypedef(String),
+}
+
+impl Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::UnknownType(ty) => write!(
+                f,
+                "Unable to resolve type '{}': it isn't a known type in this namespace or any enclosing one",
+                ty
+            ),
+            ConvertError::AmbiguousType(ty) => write!(
+                f,
+                "Type '{}' is ambiguous: it matches known types in more than one namespace",
+                ty
+            ),
+            ConvertError::RecursiveTypedef(ty) => write!(
+                f,
+                "Typedef '{}' is self-referential and has no underlying type",
+                ty
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+/// Owns the pieces of conversion state (`TypeConverter`, `TypeDatabase`)
+/// that are built up while walking bindgen's output, and drives the steps
+/// of turning that output into the final Rust/C++ bridge.
+///
+/// This is deliberately thin: the heavy lifting for any one step lives in
+/// the module dedicated to it (`type_converter`, `overload_tracker`,
+/// `trait_impl_generator`); this struct is just the call site that wires
+/// them together as bindgen items are registered and, later, as the
+/// generated bridge is assembled.
+pub(crate) struct BridgeConverter {
+    pub(crate) type_converter: TypeConverter,
+    pub(crate) type_database: TypeDatabase,
+}
+
+impl BridgeConverter {
+    pub(crate) fn new() -> Self {
+        Self {
+            type_converter: TypeConverter::new(),
+            type_database: TypeDatabase::new(),
+        }
+    }
+
+    /// Called while walking a class's AST, once we've determined it has at
+    /// least one virtual method: records them against `owner` so that a
+    /// later call to `generate_polymorphic_glue` knows to generate trait
+    /// impl glue for this class.
+    pub(crate) fn register_virtual_methods(
+        &mut self,
+        owner: TypeName,
+        methods: Vec<VirtualMethod>,
+    ) {
+        for method in methods {
+            self.type_database
+                .note_virtual_method(owner.clone(), method);
+        }
+    }
+
+    /// The main codegen step for polymorphic classes: generates the Rust
+    /// trait and C++ thunk glue for every class we noted virtual methods
+    /// on via `register_virtual_methods`, so it can be folded into the
+    /// rest of the generated bridge.
+    pub(crate) fn generate_polymorphic_glue(
+        &self,
+        ns: &Namespace,
+    ) -> Result<Vec<TraitImplGlue>, ConvertError> {
+        self.type_database
+            .polymorphic_types()
+            .map(|class_name| {
+                trait_impl_generator::generate_trait_impl_glue(
+                    class_name,
+                    &self.type_converter,
+                    &self.type_database,
+                    ns,
+                )
+            })
+            .collect()
+    }
+
+    /// Folds the glue for every polymorphic class into the two blobs that
+    /// the rest of the generated bridge actually needs: the complete C++
+    /// text (one synthesized subclass per class) and the complete set of
+    /// Rust items (one trait plus its trampolines per class).
+    pub(crate) fn assemble_polymorphic_bridge(
+        &self,
+        ns: &Namespace,
+    ) -> Result<PolymorphicBridge, ConvertError> {
+        let glue = self.generate_polymorphic_glue(ns)?;
+        let cpp = glue
+            .iter()
+            .map(|g| g.cpp_subclass.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let mut rust_items = Vec::new();
+        for g in glue {
+            rust_items.push(syn::Item::Trait(g.rust_trait));
+            rust_items.extend(g.rust_trampolines.into_iter().map(syn::Item::Fn));
+        }
+        Ok(PolymorphicBridge { cpp, rust_items })
+    }
+}
+
+/// The assembled output of `BridgeConverter::assemble_polymorphic_bridge`,
+/// ready to be written alongside (or spliced into) the rest of the
+/// generated C++ header and Rust bridge module.
+pub(crate) struct PolymorphicBridge {
+    pub(crate) cpp: String,
+    pub(crate) rust_items: Vec<syn::Item>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn tn(s: &str) -> TypeName {
+        TypeName::from_type_path(&syn::parse_str::<syn::TypePath>(s).unwrap())
+    }
+
+    #[test]
+    fn test_register_virtual_methods_makes_class_polymorphic() {
+        let mut bc = BridgeConverter::new();
+        let owner = tn("root::A::Shape");
+        assert!(!bc.type_database.is_polymorphic(&owner));
+        bc.register_virtual_methods(
+            owner.clone(),
+            vec![VirtualMethod::new(
+                parse_quote! { area },
+                Vec::new(),
+                Some(parse_quote! { f64 }),
+                true,
+                true,
+            )],
+        );
+        assert!(bc.type_database.is_polymorphic(&owner));
+        assert_eq!(bc.type_database.virtual_methods_of(&owner).len(), 1);
+    }
+
+    #[test]
+    fn test_generate_polymorphic_glue_covers_every_registered_class() {
+        let mut bc = BridgeConverter::new();
+        let owner = tn("root::A::Shape");
+        bc.type_converter
+            .push_with_namespace(owner.clone(), Namespace::from_user_input("A"));
+        bc.register_virtual_methods(
+            owner,
+            vec![VirtualMethod::new(
+                parse_quote! { area },
+                Vec::new(),
+                Some(parse_quote! { f64 }),
+                true,
+                true,
+            )],
+        );
+        let glue = bc
+            .generate_polymorphic_glue(&Namespace::new())
+            .expect("a simple virtual method should generate cleanly");
+        assert_eq!(glue.len(), 1);
+        assert_eq!(glue[0].cpp_thunks.len(), 1);
+    }
+
+    #[test]
+    fn test_assemble_polymorphic_bridge_folds_every_class_into_one_output() {
+        let mut bc = BridgeConverter::new();
+        let owner = tn("root::A::Shape");
+        bc.type_converter
+            .push_with_namespace(owner.clone(), Namespace::from_user_input("A"));
+        bc.register_virtual_methods(
+            owner,
+            vec![VirtualMethod::new(
+                parse_quote! { area },
+                Vec::new(),
+                Some(parse_quote! { f64 }),
+                true,
+                true,
+            )],
+        );
+        let bridge = bc
+            .assemble_polymorphic_bridge(&Namespace::new())
+            .expect("a simple virtual method should generate cleanly");
+        assert!(bridge.cpp.contains("class ShapeFromRust : public A::Shape"));
+        // One trait item plus one trampoline per method plus the drop
+        // trampoline.
+        assert_eq!(bridge.rust_items.len(), 3);
+    }
+}
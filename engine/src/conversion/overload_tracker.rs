@@ -14,6 +14,8 @@
 
 use std::collections::HashMap;
 
+use crate::types::TypeName;
+
 #[derive(PartialEq, Debug)]
 pub(crate) struct MethodOverload {
     pub(crate) cpp_method_name: String,
@@ -46,28 +48,65 @@ pub(crate) fn split_name(found_name: &str) -> (&str, usize) {
     panic!("Identifier was entirely numeric");
 }
 
-type Offsets = HashMap<String, usize>;
+/// A fingerprint which uniquely identifies a C++ overload's parameter
+/// list. Two genuinely distinct overloads can't produce the same
+/// fingerprint, because C++ itself wouldn't allow two overloads with
+/// identical parameter types.
+fn signature_fingerprint(arg_types: &[TypeName]) -> String {
+    arg_types
+        .iter()
+        .map(|t| t.to_cpp_name())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Insertion-ordered registry of the distinct parameter-type fingerprints
+/// seen for a single C++ base name: the first fingerprint seen gets index
+/// 0 (the bare name), and each subsequent distinct fingerprint gets the
+/// next index along. Re-seeing a fingerprint returns its existing index,
+/// which is what makes re-processing the same bindgen output idempotent.
+#[derive(Default)]
+struct OverloadRegistry {
+    index_by_fingerprint: HashMap<String, usize>,
+}
+
+impl OverloadRegistry {
+    fn assign(&mut self, fingerprint: String) -> usize {
+        let next_index = self.index_by_fingerprint.len();
+        *self
+            .index_by_fingerprint
+            .entry(fingerprint)
+            .or_insert(next_index)
+    }
+}
+
+type RegistryKey = (String, String); // (type name, or "" for free functions; base name)
 
 /// Registry of all the overloads of a function found within a given
 /// namespace (i.e. mod in bindgen's output).
-/// The idea here is that bindgen will output a series of overridden
-/// 'foo' functions as foo, foo1, foo2.
-/// We will recognize that sequence and call the correct underlying
-/// C++ function ("foo" in all cases).
-/// For extra complexity, if multiple types each have 'foo' methods
-/// it's part of the same global numbering series within bindgen
-/// output, whereas we would like to use plain old 'foo' as the method
-/// names in the impl blocks we generate. This is more important than
-/// it seems, because otherwise two different types with a 'get()'
-/// method would instead have a 'get()' and 'get1()' method in the
-/// bindings we generate.
+///
+/// The primary mechanism is signature-keyed and order-independent: for
+/// each (type, C++ base name) we remember, in the order we first saw
+/// them, the distinct parameter-type fingerprints bindgen's overloads
+/// have, and hand out `name`, `name1`, `name2`... accordingly. This
+/// doesn't depend on bindgen emitting overloads under a contiguous global
+/// numbering, so reordering or interleaving overloads of different
+/// functions can't mislabel anything.
+///
+/// For callers which don't yet have the resolved argument types to hand,
+/// we fall back to the old heuristic of parsing the numeric suffix
+/// bindgen appended to the Rust identifier (`foo`, `foo1`, `foo2`), via
+/// `split_name`. A numeric suffix on a base name we've never otherwise
+/// seen is assumed to be part of a genuine identifier (e.g. `insert2`)
+/// rather than an overload marker.
 /// See also `bridge_name_tracker`: there's a big comment
 /// there explaining the relationship of all the names.
 #[derive(Default)]
 pub(crate) struct OverloadTracker {
-    offset_by_name: Offsets,
-    offset_by_type_and_name: HashMap<String, Offsets>,
-    expected_next_by_name: HashMap<String, usize>,
+    registries: HashMap<RegistryKey, OverloadRegistry>,
+    // Fallback-only bookkeeping, used when no signature is available.
+    offset_by_name: HashMap<RegistryKey, usize>,
+    expected_next_by_name: HashMap<RegistryKey, usize>,
 }
 
 impl OverloadTracker {
@@ -75,23 +114,88 @@ impl OverloadTracker {
         Self::default()
     }
 
+    /// Falls back to guessing an overload's identity from bindgen's
+    /// numbered Rust identifier (see `split_name`). Prefer
+    /// [`Self::get_function_real_name_with_signature`] wherever the
+    /// resolved argument types are available, since it's both order-
+    /// independent and immune to the "genuine identifier named `insert2`"
+    /// false positive.
     pub(crate) fn get_function_real_name(&mut self, found_name: &str) -> MethodOverload {
-        self.next_offset(None, found_name)
+        self.next_name_by_ordinal(None, found_name)
     }
 
+    /// As [`Self::get_function_real_name`], but for a method on `type_name`.
     pub(crate) fn get_method_real_name(
         &mut self,
         type_name: &str,
         found_name: &str,
     ) -> MethodOverload {
-        self.next_offset(Some(type_name), found_name)
+        self.next_name_by_ordinal(Some(type_name), found_name)
+    }
+
+    /// As [`Self::get_function_real_name`], but signature-keyed and
+    /// order-independent: callers which already have the resolved
+    /// argument `TypeName`s to hand should prefer this.
+    pub(crate) fn get_function_real_name_with_signature(
+        &mut self,
+        cpp_name: &str,
+        arg_types: &[TypeName],
+    ) -> MethodOverload {
+        self.next_name_by_signature(None, cpp_name, arg_types)
+    }
+
+    /// As [`Self::get_method_real_name`], but signature-keyed and
+    /// order-independent; see
+    /// [`Self::get_function_real_name_with_signature`].
+    pub(crate) fn get_method_real_name_with_signature(
+        &mut self,
+        type_name: &str,
+        cpp_name: &str,
+        arg_types: &[TypeName],
+    ) -> MethodOverload {
+        self.next_name_by_signature(Some(type_name), cpp_name, arg_types)
+    }
+
+    fn next_name_by_signature(
+        &mut self,
+        type_name: Option<&str>,
+        cpp_name: &str,
+        arg_types: &[TypeName],
+    ) -> MethodOverload {
+        self.next_name_by_fingerprint(type_name, cpp_name, signature_fingerprint(arg_types))
+    }
+
+    /// As [`Self::next_name_by_signature`], but for callers (such as the
+    /// virtual-method trait generator) which already have their own
+    /// notion of a parameter-list fingerprint rather than a slice of
+    /// [`TypeName`]s.
+    pub(crate) fn next_name_by_fingerprint(
+        &mut self,
+        type_name: Option<&str>,
+        cpp_name: &str,
+        fingerprint: String,
+    ) -> MethodOverload {
+        let key = (type_name.unwrap_or("").to_string(), cpp_name.to_string());
+        let registry = self.registries.entry(key).or_default();
+        let index = registry.assign(fingerprint);
+        let rust_name = if index == 0 {
+            cpp_name.to_string()
+        } else {
+            format!("{}{}", cpp_name, index)
+        };
+        MethodOverload::new(cpp_name.to_string(), rust_name)
     }
 
-    fn next_offset(&mut self, type_name: Option<&str>, found_name: &str) -> MethodOverload {
+    fn next_name_by_ordinal(
+        &mut self,
+        type_name: Option<&str>,
+        found_name: &str,
+    ) -> MethodOverload {
         let (fn_name, counter) = split_name(found_name);
+        let key = (type_name.unwrap_or("").to_string(), fn_name.to_string());
         let expected_next_suffix = self
             .expected_next_by_name
-            .entry(fn_name.to_owned())
+            .entry(key.clone())
             .or_insert(0usize);
         if counter != *expected_next_suffix {
             // This is not some kind of overload thing.
@@ -101,15 +205,8 @@ impl OverloadTracker {
             // Possibly part of an overload sequence. We have no way to be sure
             // but let's assume so.
             *expected_next_suffix += 1;
-            let registry = match type_name {
-                Some(type_name) => self
-                    .offset_by_type_and_name
-                    .entry(type_name.to_string())
-                    .or_insert_with(HashMap::new),
-                None => &mut self.offset_by_name,
-            };
-            let offset = registry.entry(fn_name.to_string()).or_insert(counter);
-            let effective_count = counter - *offset;
+            let offset = *self.offset_by_name.entry(key).or_insert(counter);
+            let effective_count = counter - offset;
             MethodOverload::new(
                 fn_name.to_string(),
                 if effective_count == 0 {
@@ -125,9 +222,81 @@ impl OverloadTracker {
 #[cfg(test)]
 mod tests {
     use super::{MethodOverload, OverloadTracker};
+    use crate::types::TypeName;
+
+    fn tn(s: &str) -> TypeName {
+        TypeName::from_type_path(&syn::parse_str::<syn::TypePath>(s).unwrap())
+    }
+
+    #[test]
+    fn test_by_function_signature() {
+        let mut ot = OverloadTracker::new();
+        assert_eq!(
+            ot.get_function_real_name_with_signature("job", &[]),
+            MethodOverload::new("job".into(), "job".into())
+        );
+        assert_eq!(
+            ot.get_function_real_name_with_signature("job", &[tn("i32")]),
+            MethodOverload::new("job".into(), "job1".into())
+        );
+        assert_eq!(
+            ot.get_function_real_name_with_signature("job", &[tn("i32"), tn("i32")]),
+            MethodOverload::new("job".into(), "job2".into())
+        );
+        // Re-processing the same signatures, in a different order, must
+        // return exactly the same names it did before.
+        assert_eq!(
+            ot.get_function_real_name_with_signature("job", &[tn("i32"), tn("i32")]),
+            MethodOverload::new("job".into(), "job2".into())
+        );
+        assert_eq!(
+            ot.get_function_real_name_with_signature("job", &[]),
+            MethodOverload::new("job".into(), "job".into())
+        );
+    }
+
+    #[test]
+    fn test_by_method_signature_order_independent_and_per_type() {
+        let mut ot = OverloadTracker::new();
+        assert_eq!(
+            ot.get_method_real_name_with_signature("A", "do", &[]),
+            MethodOverload::new("do".into(), "do".into())
+        );
+        // Interleave a different type's overloads of the same base name;
+        // this must not perturb A's numbering.
+        assert_eq!(
+            ot.get_method_real_name_with_signature("B", "do", &[tn("i32")]),
+            MethodOverload::new("do".into(), "do".into())
+        );
+        assert_eq!(
+            ot.get_method_real_name_with_signature("A", "do", &[tn("i32")]),
+            MethodOverload::new("do".into(), "do1".into())
+        );
+        assert_eq!(
+            ot.get_method_real_name_with_signature("B", "do", &[]),
+            MethodOverload::new("do".into(), "do1".into())
+        );
+    }
 
     #[test]
-    fn test_by_function() {
+    fn test_by_fingerprint() {
+        let mut ot = OverloadTracker::new();
+        assert_eq!(
+            ot.next_name_by_fingerprint(Some("A"), "Foo", "int".into()),
+            MethodOverload::new("Foo".into(), "Foo".into())
+        );
+        assert_eq!(
+            ot.next_name_by_fingerprint(Some("A"), "Foo", "double".into()),
+            MethodOverload::new("Foo".into(), "Foo1".into())
+        );
+        assert_eq!(
+            ot.next_name_by_fingerprint(Some("A"), "Foo", "int".into()),
+            MethodOverload::new("Foo".into(), "Foo".into())
+        );
+    }
+
+    #[test]
+    fn test_fallback_by_ordinal() {
         let mut ot = OverloadTracker::new();
         assert_eq!(
             ot.get_function_real_name("job"),
@@ -156,7 +325,7 @@ mod tests {
     }
 
     #[test]
-    fn test_by_method() {
+    fn test_fallback_by_ordinal_is_per_type() {
         let mut ot = OverloadTracker::new();
         assert_eq!(
             ot.get_method_real_name("A", "do"),
@@ -174,16 +343,10 @@ mod tests {
             ot.get_method_real_name("A", "dog1"),
             MethodOverload::new("dog".into(), "dog1".into())
         );
+        // Type B has never seen a bare "do", so "do2" here is a genuine
+        // identifier, not an overload continuation.
         assert_eq!(
             ot.get_method_real_name("B", "do2"),
-            MethodOverload::new("do".into(), "do".into())
-        );
-        assert_eq!(
-            ot.get_method_real_name("B", "do3"),
-            MethodOverload::new("do".into(), "do1".into())
-        );
-        assert_eq!(
-            ot.get_method_real_name("C", "do2"),
             MethodOverload::new("do2".into(), "do2".into())
         );
         assert_eq!(
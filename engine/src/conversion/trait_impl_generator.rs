@@ -0,0 +1,523 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use quote::{format_ident, quote};
+use syn::parse_quote;
+
+use crate::{
+    conversion::{
+        bridge_converter::ConvertError, overload_tracker::OverloadTracker,
+        type_converter::TypeConverter,
+    },
+    type_database::{TypeDatabase, VirtualMethod},
+    types::{Namespace, TypeName},
+};
+
+/// Everything generated so that a Rust type can implement a C++ abstract
+/// base class: the Rust trait mirroring its virtual methods, plus the
+/// C++ glue which, for each virtual method, dispatches the call back
+/// into whatever Rust type implements that trait.
+///
+/// This is the trait-impl equivalent of what a C-binding generator does
+/// for ordinary methods, which it turns into mapped free-standing
+/// functions: here, instead, each virtual method becomes a trait method
+/// plus a C++ override which calls back into Rust.
+pub(crate) struct TraitImplGlue {
+    pub(crate) rust_trait: syn::ItemTrait,
+    /// One C++ `override` member function per virtual method, hosted in
+    /// `cpp_subclass`, each calling back into the matching `extern "C"`
+    /// trampoline in `rust_trampolines`.
+    pub(crate) cpp_thunks: Vec<String>,
+    /// The synthesized C++ subclass of the polymorphic class: it owns the
+    /// boxed Rust trait object (`rust_obj_`) and hosts `cpp_thunks` as its
+    /// overrides, so an instance of it can be handed to C++ code wherever
+    /// a pointer to the base class is expected.
+    pub(crate) cpp_subclass: String,
+    /// The `extern "C"` trampolines that `cpp_subclass`'s overrides call:
+    /// one per virtual method, dispatching into the boxed trait object,
+    /// plus one which drops that trait object when the subclass is
+    /// destroyed.
+    pub(crate) rust_trampolines: Vec<syn::ItemFn>,
+}
+
+/// For a single polymorphic C++ class, generate the Rust trait and C++
+/// thunk glue which let a Rust type stand in for that class wherever C++
+/// code expects a pointer to it.
+pub(crate) fn generate_trait_impl_glue(
+    class_name: &TypeName,
+    type_converter: &TypeConverter,
+    type_database: &TypeDatabase,
+    ns: &Namespace,
+) -> Result<TraitImplGlue, ConvertError> {
+    let methods = type_database.virtual_methods_of(class_name);
+    let trait_ident = format_ident!("{}Trait", class_name.get_final_ident());
+    // C++ allows virtual methods to be overloaded (same name, different
+    // parameters); Rust traits don't, so each distinct overload needs its
+    // own Rust-facing name. Reuse the same signature-keyed, order-
+    // independent scheme used for ordinary overloaded methods.
+    let mut overloads = OverloadTracker::new();
+    let mut trait_methods = Vec::new();
+    let mut cpp_thunks = Vec::new();
+    let mut rust_trampolines = Vec::new();
+    for method in methods {
+        let rust_name = overloads
+            .next_name_by_fingerprint(
+                Some(&class_name.to_cpp_name()),
+                &method.name.to_string(),
+                param_fingerprint(method),
+            )
+            .rust_method_name;
+        let rust_name = format_ident!("{}", rust_name);
+        // Route params/return through the same pointer-to-reference
+        // conversion for both the trait method and the C++ thunk, so a
+        // raw-pointer virtual method param/return doesn't reach
+        // `type_to_cpp`, which doesn't understand `Type::Ptr`.
+        let converted_params = method
+            .params
+            .iter()
+            .map(|(ident, ty)| Ok((ident.clone(), type_converter.convert_type(ty.clone(), ns)?)))
+            .collect::<Result<Vec<_>, ConvertError>>()?;
+        let converted_return_type = method
+            .return_type
+            .as_ref()
+            .map(|ty| type_converter.convert_type(ty.clone(), ns))
+            .transpose()?;
+        trait_methods.push(generate_trait_method(
+            &rust_name,
+            &converted_params,
+            &converted_return_type,
+            method.is_pure,
+        ));
+        cpp_thunks.push(generate_cpp_thunk(
+            class_name,
+            &rust_name,
+            method,
+            &converted_params,
+            &converted_return_type,
+            type_database,
+        ));
+        rust_trampolines.push(generate_rust_trampoline(
+            class_name,
+            &trait_ident,
+            &rust_name,
+            &converted_params,
+            &converted_return_type,
+        ));
+    }
+    rust_trampolines.push(generate_rust_drop_trampoline(class_name, &trait_ident));
+    let rust_trait: syn::ItemTrait = parse_quote! {
+        pub trait #trait_ident {
+            #(#trait_methods)*
+        }
+    };
+    let cpp_subclass = generate_cpp_subclass(class_name, &cpp_thunks);
+    Ok(TraitImplGlue {
+        rust_trait,
+        cpp_thunks,
+        cpp_subclass,
+        rust_trampolines,
+    })
+}
+
+/// The name of the C++ subclass synthesized to own a boxed Rust trait
+/// object and host the thunk overrides for `class_name`.
+fn cpp_subclass_name(class_name: &TypeName) -> String {
+    format!("{}FromRust", class_name.get_final_ident())
+}
+
+/// The `extern "C"` symbol name shared by a thunk and the trampoline it
+/// calls, unique per class and per (disambiguated) Rust method name.
+fn trampoline_name(class_name: &TypeName, suffix: &str) -> String {
+    format!(
+        "autocxx_trampoline_{}_{}",
+        class_name.to_cpp_name().replace("::", "_"),
+        suffix
+    )
+}
+
+/// A fingerprint of a virtual method's parameter list, good enough to
+/// distinguish one C++ overload from another (C++ itself forbids two
+/// overloads with identical parameter types).
+fn param_fingerprint(method: &VirtualMethod) -> String {
+    method
+        .params
+        .iter()
+        .map(|(_, ty)| quote!(#ty).to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn generate_trait_method(
+    rust_name: &syn::Ident,
+    converted_params: &[(syn::Ident, syn::Type)],
+    converted_return_type: &Option<syn::Type>,
+    is_pure: bool,
+) -> syn::TraitItemMethod {
+    let param_idents: Vec<_> = converted_params.iter().map(|(ident, _)| ident).collect();
+    let param_types: Vec<_> = converted_params.iter().map(|(_, ty)| ty).collect();
+    if is_pure {
+        // A pure virtual method has no C++ implementation to fall back to,
+        // so every Rust type implementing this trait must override it.
+        return match converted_return_type {
+            None => parse_quote! {
+                fn #rust_name(&self #(, #param_idents: #param_types)*);
+            },
+            Some(return_type) => parse_quote! {
+                fn #rust_name(&self #(, #param_idents: #param_types)*) -> #return_type;
+            },
+        };
+    }
+    // A non-pure virtual method already has a C++ implementation; we have
+    // no way to call back into it from the default trait method (that
+    // would require plumbing the original C++ instance through to Rust,
+    // which this glue doesn't do), so we make the override optional and
+    // spell that gap out explicitly rather than silently generating an
+    // override identical to the pure case.
+    match converted_return_type {
+        None => parse_quote! {
+            fn #rust_name(&self #(, #param_idents: #param_types)*) {
+                unimplemented!("non-pure virtual method not overridden by this Rust implementation")
+            }
+        },
+        Some(return_type) => parse_quote! {
+            fn #rust_name(&self #(, #param_idents: #param_types)*) -> #return_type {
+                unimplemented!("non-pure virtual method not overridden by this Rust implementation")
+            }
+        },
+    }
+}
+
+/// Generates the C++ override which the synthesized subclass produced by
+/// `generate_cpp_subclass` uses to dispatch a virtual call into whatever
+/// Rust type implements the corresponding trait. The `extern "C"`
+/// function it calls is generated by `generate_rust_trampoline`.
+fn generate_cpp_thunk(
+    class_name: &TypeName,
+    rust_name: &syn::Ident,
+    method: &VirtualMethod,
+    converted_params: &[(syn::Ident, syn::Type)],
+    converted_return_type: &Option<syn::Type>,
+    type_database: &TypeDatabase,
+) -> String {
+    let return_cpp = converted_return_type
+        .as_ref()
+        .map(|ty| type_database.type_to_cpp(ty))
+        .unwrap_or_else(|| "void".to_string());
+    let params_cpp = converted_params
+        .iter()
+        .map(|(ident, ty)| format!("{} {}", type_database.type_to_cpp(ty), ident))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let arg_names = converted_params
+        .iter()
+        .map(|(ident, _)| ident.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    // The trampoline symbol is named after the disambiguated Rust method,
+    // not the (possibly overloaded) C++ one, so that each overload gets
+    // its own distinct extern "C" entry point.
+    let trampoline = trampoline_name(class_name, &rust_name.to_string());
+    let maybe_comma = if arg_names.is_empty() { "" } else { ", " };
+    let const_qualifier = if method.is_const { " const" } else { "" };
+    format!(
+        "{} {}({}){} override {{ return {}(rust_obj_{}{}); }}",
+        return_cpp, method.name, params_cpp, const_qualifier, trampoline, maybe_comma, arg_names
+    )
+}
+
+/// Generates the `extern "C"` trampoline that a thunk produced by
+/// `generate_cpp_thunk` for the same method calls: it recovers the boxed
+/// Rust trait object from the opaque pointer the subclass stores in
+/// `rust_obj_` and dispatches the call into it.
+///
+/// The trait object is boxed twice (`Box<Box<dyn Trait>>`, unwrapped back
+/// to `&Box<dyn Trait>` here) so that the pointer crossing the FFI
+/// boundary is a single thin pointer rather than a fat trait-object
+/// pointer, which C++ has no way to represent.
+fn generate_rust_trampoline(
+    class_name: &TypeName,
+    trait_ident: &syn::Ident,
+    rust_name: &syn::Ident,
+    converted_params: &[(syn::Ident, syn::Type)],
+    converted_return_type: &Option<syn::Type>,
+) -> syn::ItemFn {
+    let trampoline_ident = format_ident!("{}", trampoline_name(class_name, &rust_name.to_string()));
+    let param_idents: Vec<_> = converted_params.iter().map(|(ident, _)| ident).collect();
+    let param_types: Vec<_> = converted_params.iter().map(|(_, ty)| ty).collect();
+    match converted_return_type {
+        None => parse_quote! {
+            #[no_mangle]
+            pub extern "C" fn #trampoline_ident(
+                rust_obj: *const std::ffi::c_void
+                #(, #param_idents: #param_types)*
+            ) {
+                let rust_obj = unsafe { &*(rust_obj as *const Box<dyn #trait_ident>) };
+                rust_obj.#rust_name(#(#param_idents),*);
+            }
+        },
+        Some(return_type) => parse_quote! {
+            #[no_mangle]
+            pub extern "C" fn #trampoline_ident(
+                rust_obj: *const std::ffi::c_void
+                #(, #param_idents: #param_types)*
+            ) -> #return_type {
+                let rust_obj = unsafe { &*(rust_obj as *const Box<dyn #trait_ident>) };
+                rust_obj.#rust_name(#(#param_idents),*)
+            }
+        },
+    }
+}
+
+/// Generates the `extern "C"` trampoline that the synthesized subclass's
+/// destructor calls to drop the boxed Rust trait object it owns.
+fn generate_rust_drop_trampoline(class_name: &TypeName, trait_ident: &syn::Ident) -> syn::ItemFn {
+    let trampoline_ident = format_ident!("{}", trampoline_name(class_name, "drop"));
+    parse_quote! {
+        #[no_mangle]
+        pub extern "C" fn #trampoline_ident(rust_obj: *mut std::ffi::c_void) {
+            unsafe {
+                drop(Box::from_raw(rust_obj as *mut Box<dyn #trait_ident>));
+            }
+        }
+    }
+}
+
+/// Generates the C++ subclass of `class_name` which owns the boxed Rust
+/// trait object (`rust_obj_`) and hosts `cpp_thunks` as its virtual
+/// method overrides, so an instance of it can be handed to C++ code
+/// wherever a pointer to `class_name` is expected.
+fn generate_cpp_subclass(class_name: &TypeName, cpp_thunks: &[String]) -> String {
+    let subclass_name = cpp_subclass_name(class_name);
+    let drop_trampoline = trampoline_name(class_name, "drop");
+    let overrides = cpp_thunks
+        .iter()
+        .map(|thunk| format!("    {}", thunk))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "class {name} : public {base} {{\npublic:\n    explicit {name}(void* rust_obj) : rust_obj_(rust_obj) {{}}\n    ~{name}() override {{ {drop}(rust_obj_); }}\n{overrides}\nprivate:\n    void* rust_obj_;\n}};",
+        name = subclass_name,
+        base = class_name.to_cpp_name(),
+        drop = drop_trampoline,
+        overrides = overrides,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn tn(s: &str) -> TypeName {
+        TypeName::from_type_path(&syn::parse_str::<syn::TypePath>(s).unwrap())
+    }
+
+    fn shape_namespace() -> Namespace {
+        Namespace::from_user_input("A")
+    }
+
+    fn type_converter_with_shape_registered() -> TypeConverter {
+        let mut type_converter = TypeConverter::new();
+        type_converter.push_with_namespace(tn("root::A::Shape"), shape_namespace());
+        type_converter
+    }
+
+    #[test]
+    fn test_raw_pointer_param_and_return_become_references_in_thunk() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            db.note_virtual_method(
+                tn("root::A::Shape"),
+                VirtualMethod::new(
+                    parse_quote! { clone_into },
+                    vec![(parse_quote! { out }, parse_quote! { *mut Shape })],
+                    Some(parse_quote! { *mut Shape }),
+                    false,
+                    true,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("a raw-pointer virtual method must not panic in type_to_cpp");
+        assert_eq!(glue.cpp_thunks.len(), 1);
+        assert!(
+            glue.cpp_thunks[0].starts_with("A::Shape& clone_into(A::Shape& out) const override")
+        );
+    }
+
+    #[test]
+    fn test_overloaded_virtual_methods_get_distinct_names() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            let owner = tn("root::A::Shape");
+            db.note_virtual_method(
+                owner.clone(),
+                VirtualMethod::new(parse_quote! { scale }, Vec::new(), None, true, false),
+            );
+            db.note_virtual_method(
+                owner,
+                VirtualMethod::new(
+                    parse_quote! { scale },
+                    vec![(parse_quote! { factor }, parse_quote! { f64 })],
+                    None,
+                    true,
+                    false,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("distinct overloads should generate cleanly");
+        assert_eq!(glue.cpp_thunks.len(), 2);
+        assert!(glue.cpp_thunks[0].contains("autocxx_trampoline_A_Shape_scale("));
+        assert!(glue.cpp_thunks[1].contains("autocxx_trampoline_A_Shape_scale1("));
+    }
+
+    #[test]
+    fn test_cpp_subclass_owns_rust_obj_and_hosts_thunks() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            db.note_virtual_method(
+                tn("root::A::Shape"),
+                VirtualMethod::new(
+                    parse_quote! { area },
+                    Vec::new(),
+                    Some(parse_quote! { f64 }),
+                    true,
+                    true,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("a simple virtual method should generate cleanly");
+        assert!(glue
+            .cpp_subclass
+            .starts_with("class ShapeFromRust : public A::Shape {"));
+        assert!(glue.cpp_subclass.contains("void* rust_obj_;"));
+        assert!(glue.cpp_subclass.contains(&glue.cpp_thunks[0]));
+        assert!(glue
+            .cpp_subclass
+            .contains("autocxx_trampoline_A_Shape_drop(rust_obj_)"));
+    }
+
+    #[test]
+    fn test_rust_trampolines_include_one_per_method_plus_drop() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            db.note_virtual_method(
+                tn("root::A::Shape"),
+                VirtualMethod::new(
+                    parse_quote! { area },
+                    Vec::new(),
+                    Some(parse_quote! { f64 }),
+                    true,
+                    true,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("a simple virtual method should generate cleanly");
+        assert_eq!(glue.rust_trampolines.len(), 2);
+        let names: Vec<String> = glue
+            .rust_trampolines
+            .iter()
+            .map(|f| f.sig.ident.to_string())
+            .collect();
+        assert!(names.contains(&"autocxx_trampoline_A_Shape_area".to_string()));
+        assert!(names.contains(&"autocxx_trampoline_A_Shape_drop".to_string()));
+    }
+
+    #[test]
+    fn test_pure_virtual_trait_method_has_no_default_body() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            db.note_virtual_method(
+                tn("root::A::Shape"),
+                VirtualMethod::new(
+                    parse_quote! { area },
+                    Vec::new(),
+                    Some(parse_quote! { f64 }),
+                    true,
+                    true,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("a simple virtual method should generate cleanly");
+        let item = &glue.rust_trait.items[0];
+        assert!(!quote!(#item).to_string().contains("unimplemented"));
+    }
+
+    #[test]
+    fn test_non_pure_virtual_trait_method_gets_default_body() {
+        let type_converter = type_converter_with_shape_registered();
+        let type_database = {
+            let mut db = TypeDatabase::new();
+            db.note_virtual_method(
+                tn("root::A::Shape"),
+                VirtualMethod::new(
+                    parse_quote! { area },
+                    Vec::new(),
+                    Some(parse_quote! { f64 }),
+                    false,
+                    true,
+                ),
+            );
+            db
+        };
+        let glue = generate_trait_impl_glue(
+            &tn("root::A::Shape"),
+            &type_converter,
+            &type_database,
+            &shape_namespace(),
+        )
+        .expect("a simple virtual method should generate cleanly");
+        let item = &glue.rust_trait.items[0];
+        assert!(quote!(#item).to_string().contains("unimplemented"));
+    }
+}
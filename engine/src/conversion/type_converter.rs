@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use syn::{
     parse_quote, punctuated::Punctuated, GenericArgument, PathArguments, PathSegment, Type,
@@ -25,11 +25,18 @@ use crate::{
     types::{Namespace, TypeName},
 };
 
-use super::typedef_analyzer::{TypedefTarget, analyze_typedef_target};
+use super::typedef_analyzer::{analyze_typedef_target, TypedefTarget};
 
 pub(crate) struct TypeConverter {
     types_found: Vec<TypeName>,
     typedefs: HashMap<TypeName, TypedefTarget>,
+    // Maps each discovered type to the namespace in which it was found, so
+    // that an unqualified reference can be resolved by searching outward
+    // from the point of use rather than just assuming the current namespace.
+    namespaces_by_type: HashMap<TypeName, Namespace>,
+    // Explicit `using` (C++) / `use` aliases in scope, keyed by the alias
+    // name and the namespace in which the alias was declared.
+    type_aliases: HashMap<(Namespace, TypeName), TypeName>,
 }
 
 impl TypeConverter {
@@ -37,13 +44,24 @@ impl TypeConverter {
         Self {
             types_found: Vec::new(),
             typedefs: HashMap::new(),
+            namespaces_by_type: HashMap::new(),
+            type_aliases: HashMap::new(),
         }
     }
 
     pub(crate) fn push(&mut self, ty: TypeName) {
+        self.push_with_namespace(ty, Namespace::new())
+    }
+
+    pub(crate) fn push_with_namespace(&mut self, ty: TypeName, ns: Namespace) {
+        self.namespaces_by_type.insert(ty.clone(), ns);
         self.types_found.push(ty);
     }
 
+    pub(crate) fn insert_alias(&mut self, ns: Namespace, alias: TypeName, target: TypeName) {
+        self.type_aliases.insert((ns, alias), target);
+    }
+
     pub(crate) fn insert_typedef(&mut self, id: TypeName, ty: &Type) {
         let target = analyze_typedef_target(ty);
         self.typedefs.insert(id, target);
@@ -57,22 +75,26 @@ impl TypeConverter {
         Ok(Box::new(self.convert_type(*ty, ns)?))
     }
 
-    fn convert_type(&self, ty: Type, ns: &Namespace) -> Result<Type, ConvertError> {
+    pub(crate) fn convert_type(&self, ty: Type, ns: &Namespace) -> Result<Type, ConvertError> {
         let result = match ty {
-            Type::Path(p) => {
-                let newp = self.convert_type_path(p, ns)?;
-                // Special handling because rust_Str (as emitted by bindgen)
-                // doesn't simply get renamed to a different type _identifier_.
-                // This plain type-by-value (as far as bindgen is concerned)
-                // is actually a &str.
-                if should_dereference_in_cpp(&newp) {
-                    Type::Reference(parse_quote! {
-                        &str
-                    })
-                } else {
-                    Type::Path(newp)
+            Type::Path(p) => match self.convert_type_path(p, ns)? {
+                Type::Path(newp) => {
+                    // Special handling because rust_Str (as emitted by
+                    // bindgen) doesn't simply get renamed to a different
+                    // type _identifier_. This plain type-by-value (as far
+                    // as bindgen is concerned) is actually a &str.
+                    if should_dereference_in_cpp(&newp) {
+                        Type::Reference(parse_quote! {
+                            &str
+                        })
+                    } else {
+                        Type::Path(newp)
+                    }
                 }
-            }
+                // A typedef can expand to something which isn't a plain
+                // path at all, e.g. a pointer or reference type.
+                other => other,
+            },
             Type::Reference(mut r) => {
                 r.elem = self.convert_boxed_type(r.elem, ns)?;
                 Type::Reference(r)
@@ -83,11 +105,7 @@ impl TypeConverter {
         Ok(result)
     }
 
-    fn convert_type_path(
-        &self,
-        mut typ: TypePath,
-        ns: &Namespace,
-    ) -> Result<TypePath, ConvertError> {
+    fn convert_type_path(&self, mut typ: TypePath, ns: &Namespace) -> Result<Type, ConvertError> {
         if typ.path.segments.iter().next().unwrap().ident == "root" {
             typ.path.segments = typ
                 .path
@@ -107,13 +125,18 @@ impl TypeConverter {
                 .collect::<Result<_, _>>()?;
         } else {
             let ty = TypeName::from_type_path(&typ);
-            // If the type looks like it is unqualified, check we know it
-            // already, and if not, qualify it according to the current
-            // namespace. This is a bit of a shortcut compared to having a full
-            // resolution pass which can search all known namespaces.
-            if !self.types_found.contains(&ty) && !is_known_type(&ty) {
-                typ.path.segments = std::iter::once(&"root".to_string())
-                    .chain(ns.iter())
+            if let Some(target) = self.type_aliases.get(&(ns.clone(), ty.clone())) {
+                // An explicit `use`/`using` alias always wins.
+                let args = typ.path.segments.last().unwrap().arguments.clone();
+                typ = target.to_type_path();
+                typ.path.segments.last_mut().unwrap().arguments = args;
+            } else if !self.types_found.contains(&ty) && !is_known_type(&ty) {
+                // The type looks unqualified (or only partially qualified).
+                // Work out which namespace it actually lives in, rather than
+                // assuming it's the namespace we're currently in.
+                let resolved_ns = self.resolve_namespace(&typ, &ty, ns)?;
+                typ.path.segments = std::iter::once("root".to_string())
+                    .chain(resolved_ns)
                     .map(|s| parse_quote! { #s })
                     .chain(typ.path.segments.into_iter())
                     .collect();
@@ -132,11 +155,13 @@ impl TypeConverter {
         }
         drop(seg_iter);
         let tn = TypeName::from_type_path(&typ);
-        // Let's see if this is a typedef.
-        let typ = self
-            .resolve_typedef(&tn)?
-            .map(|x| x.to_type_path())
-            .unwrap_or(typ);
+        // Let's see if this is a typedef. Its target might itself be
+        // templated, or add pointer/reference indirection, so rather than
+        // assuming it's a plain path we feed it back through the full
+        // conversion pipeline.
+        if let Some(expanded) = self.resolve_typedef(&tn)? {
+            return self.convert_type(expanded, ns);
+        }
 
         // This will strip off any path arguments...
         let mut typ = known_type_substitute_path(&typ).unwrap_or(typ);
@@ -145,7 +170,88 @@ impl TypeConverter {
             let last_seg = typ.path.segments.last_mut().unwrap();
             last_seg.arguments = last_seg_args;
         }
-        Ok(typ)
+        Ok(Type::Path(typ))
+    }
+
+    /// Work out which namespace an unqualified (or only partially qualified)
+    /// type reference actually resolves to.
+    ///
+    /// We first walk outward from the namespace of use towards the root,
+    /// taking the innermost enclosing namespace in which we've actually
+    /// seen this type defined (ordinary C++ scoping). Failing that, we
+    /// fall back to searching every namespace we know about, and insist on
+    /// a unique match - an ambiguous or absent match is a hard error rather
+    /// than a silently wrong guess.
+    fn resolve_namespace(
+        &self,
+        typ: &TypePath,
+        ty: &TypeName,
+        ns: &Namespace,
+    ) -> Result<Vec<String>, ConvertError> {
+        let ns_segments: Vec<String> = ns.iter().cloned().collect();
+        for depth in (0..=ns_segments.len()).rev() {
+            let candidate_ns = &ns_segments[..depth];
+            if self.is_registered_in(typ, candidate_ns) {
+                return Ok(candidate_ns.to_vec());
+            }
+        }
+        // `namespaces_by_type` is a HashMap, so iteration order is
+        // unspecified; `dedup` only collapses *adjacent* duplicates, so
+        // we must sort the candidates into a canonical order first, or two
+        // distinct types resolving to the same namespace could be counted
+        // as separate (and falsely ambiguous) candidates depending on hash
+        // iteration order.
+        let mut candidate_namespaces: Vec<Vec<String>> = self
+            .namespaces_by_type
+            .iter()
+            .filter(|(found_ty, _)| Self::same_leaf_path(typ, found_ty))
+            .map(|(_, found_ns)| {
+                // A partially qualified `typ` already spells out its own
+                // trailing qualifiers, so they mustn't be prefixed twice.
+                let found_ns: Vec<String> = found_ns.iter().cloned().collect();
+                let own_qualifiers = typ.path.segments.len() - 1;
+                found_ns[..found_ns.len().saturating_sub(own_qualifiers)].to_vec()
+            })
+            .collect();
+        candidate_namespaces.sort();
+        candidate_namespaces.dedup();
+        match candidate_namespaces.len() {
+            0 => Err(ConvertError::UnknownType(ty.to_cpp_name())),
+            1 => Ok(candidate_namespaces.into_iter().next().unwrap()),
+            _ => Err(ConvertError::AmbiguousType(ty.to_cpp_name())),
+        }
+    }
+
+    /// True if a type with this unqualified path has actually been
+    /// registered as living in `candidate_ns`.
+    fn is_registered_in(&self, typ: &TypePath, candidate_ns: &[String]) -> bool {
+        let leaf = Self::leaf_idents(typ);
+        let full = if candidate_ns.is_empty() {
+            format!("root::{}", leaf)
+        } else {
+            format!("root::{}::{}", candidate_ns.join("::"), leaf)
+        };
+        let candidate_path: TypePath = syn::parse_str(&full).expect("internally built path");
+        self.types_found
+            .contains(&TypeName::from_type_path(&candidate_path))
+    }
+
+    /// True if `found_ty`'s fully-qualified name ends in the same sequence
+    /// of identifiers as `typ`, i.e. it's a plausible match for an
+    /// unqualified or partially-qualified reference.
+    fn same_leaf_path(typ: &TypePath, found_ty: &TypeName) -> bool {
+        let leaf = Self::leaf_idents(typ);
+        let found = found_ty.to_cpp_name();
+        found == leaf || found.ends_with(&format!("::{}", leaf))
+    }
+
+    fn leaf_idents(typ: &TypePath) -> String {
+        typ.path
+            .segments
+            .iter()
+            .map(|s| s.ident.to_string())
+            .collect::<Vec<_>>()
+            .join("::")
     }
 
     fn convert_punctuated<P>(
@@ -166,19 +272,72 @@ impl TypeConverter {
         Ok(new_pun)
     }
 
-    fn resolve_typedef<'b>(
-        &'b self,
-        tn: &'b TypeName,
-    ) -> Result<Option<&'b TypeName>, ConvertError> {
-        match self.typedefs.get(&tn) {
+    fn resolve_typedef(&self, tn: &TypeName) -> Result<Option<Type>, ConvertError> {
+        self.resolve_typedef_with_cycle_check(tn, &mut HashSet::new())
+    }
+
+    /// Chase a typedef to whatever it ultimately points to, re-expanding
+    /// further typedefs along the way. `visited` guards against a
+    /// self-referential or mutually recursive typedef chain, which would
+    /// otherwise recurse forever rather than producing a clean error.
+    fn resolve_typedef_with_cycle_check(
+        &self,
+        tn: &TypeName,
+        visited: &mut HashSet<TypeName>,
+    ) -> Result<Option<Type>, ConvertError> {
+        if !visited.insert(tn.clone()) {
+            return Err(ConvertError::RecursiveTypedef(tn.to_cpp_name()));
+        }
+        match self.typedefs.get(tn) {
             None => Ok(None),
             Some(TypedefTarget::NoArguments(original_tn)) => {
-                match self.resolve_typedef(original_tn)? {
-                    None => Ok(Some(original_tn)),
+                match self.resolve_typedef_with_cycle_check(original_tn, visited)? {
+                    None => Ok(Some(Type::Path(original_tn.to_type_path()))),
+                    Some(further_resolution) => Ok(Some(further_resolution)),
+                }
+            }
+            Some(TypedefTarget::General(target_ty)) => {
+                self.chase_general_target(target_ty, visited)
+            }
+        }
+    }
+
+    /// As [`Self::resolve_typedef_with_cycle_check`], but for a
+    /// `TypedefTarget::General` target which may itself be wrapped in
+    /// pointer or reference indirection (e.g. `using A = B*;`). We must
+    /// keep threading the same `visited` set through that indirection too,
+    /// or a cycle like `using A = B*; using B = A*;` would sail straight
+    /// past the guard above and recurse forever.
+    fn chase_general_target(
+        &self,
+        target_ty: &Type,
+        visited: &mut HashSet<TypeName>,
+    ) -> Result<Option<Type>, ConvertError> {
+        match target_ty {
+            Type::Path(target_path) => {
+                let target_tn = TypeName::from_type_path(target_path);
+                match self.resolve_typedef_with_cycle_check(&target_tn, visited)? {
                     Some(further_resolution) => Ok(Some(further_resolution)),
+                    None => Ok(Some(target_ty.clone())),
                 }
             }
-            _ => Err(ConvertError::ComplexTypedefTarget(tn.to_cpp_name())),
+            Type::Ptr(ptr) => match self.chase_general_target(ptr.elem.as_ref(), visited)? {
+                Some(resolved_elem) => {
+                    let mut ptr = ptr.clone();
+                    ptr.elem = Box::new(resolved_elem);
+                    Ok(Some(Type::Ptr(ptr)))
+                }
+                None => Ok(Some(target_ty.clone())),
+            },
+            Type::Reference(r) => match self.chase_general_target(r.elem.as_ref(), visited)? {
+                Some(resolved_elem) => {
+                    let mut r = r.clone();
+                    r.elem = Box::new(resolved_elem);
+                    Ok(Some(Type::Reference(r)))
+                }
+                None => Ok(Some(target_ty.clone())),
+            },
+            _ => Ok(Some(target_ty.clone())),
         }
     }
 
@@ -198,3 +357,95 @@ impl TypeConverter {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns(path: &str) -> Namespace {
+        Namespace::from_user_input(path)
+    }
+
+    fn tn(path: &str) -> TypeName {
+        TypeName::from_type_path(&syn::parse_str::<TypePath>(path).unwrap())
+    }
+
+    fn bare_type(name: &str) -> Type {
+        syn::parse_str::<Type>(name).unwrap()
+    }
+
+    #[test]
+    fn test_resolves_type_in_sibling_namespace() {
+        let mut tc = TypeConverter::new();
+        tc.push_with_namespace(tn("root::A::C::Foo"), ns("A::C"));
+        let converted = tc
+            .convert_type(bare_type("Foo"), &ns("A::B"))
+            .expect("should find the unique match via the global fallback search");
+        assert_eq!(
+            quote::quote!(#converted).to_string(),
+            quote::quote!(root::A::C::Foo).to_string()
+        );
+    }
+
+    #[test]
+    fn test_resolves_type_via_enclosing_namespace_walk() {
+        let mut tc = TypeConverter::new();
+        tc.push_with_namespace(tn("root::A::Foo"), ns("A"));
+        // A type with an unrelated namesake further out must not be
+        // preferred: the enclosing-namespace walk should find "A::Foo"
+        // before the global fallback search ever runs.
+        tc.push_with_namespace(tn("root::Other::Foo"), ns("Other"));
+        let converted = tc
+            .convert_type(bare_type("Foo"), &ns("A::B"))
+            .expect("should find the match in the enclosing namespace 'A'");
+        assert_eq!(
+            quote::quote!(#converted).to_string(),
+            quote::quote!(root::A::Foo).to_string()
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_type_is_an_error() {
+        let mut tc = TypeConverter::new();
+        tc.push_with_namespace(tn("root::A::Foo"), ns("A"));
+        tc.push_with_namespace(tn("root::B::Foo"), ns("B"));
+        let err = tc
+            .convert_type(bare_type("Foo"), &ns("C"))
+            .expect_err("two equally plausible namespaces must be a hard error, not a guess");
+        assert!(matches!(err, ConvertError::AmbiguousType(_)));
+    }
+
+    #[test]
+    fn test_unknown_type_is_an_error() {
+        let tc = TypeConverter::new();
+        let err = tc
+            .convert_type(bare_type("Bar"), &ns("A::B"))
+            .expect_err("a type never registered anywhere can't be resolved");
+        assert!(matches!(err, ConvertError::UnknownType(_)));
+    }
+
+    #[test]
+    fn test_self_referential_typedef_is_an_error() {
+        let mut tc = TypeConverter::new();
+        tc.push(tn("A"));
+        tc.insert_typedef(tn("A"), &bare_type("A"));
+        let err = tc
+            .convert_type(bare_type("A"), &Namespace::new())
+            .expect_err("using A = A; has no underlying type");
+        assert!(matches!(err, ConvertError::RecursiveTypedef(_)));
+    }
+
+    #[test]
+    fn test_mutually_referential_pointer_typedef_is_an_error() {
+        // using A = B*; using B = A*; - the indirection through a pointer
+        // must not hide the cycle from the visited-set guard.
+        let mut tc = TypeConverter::new();
+        tc.push(tn("A"));
+        tc.insert_typedef(tn("A"), &bare_type("*mut B"));
+        tc.insert_typedef(tn("B"), &bare_type("*mut A"));
+        let err = tc
+            .convert_type(bare_type("A"), &Namespace::new())
+            .expect_err("a cycle through pointer indirection must still be caught");
+        assert!(matches!(err, ConvertError::RecursiveTypedef(_)));
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use syn::Type;
+
+use crate::types::TypeName;
+
+/// What a `typedef`/`using` declaration actually points to, as extracted
+/// from the `syn::Type` bindgen gave us for its underlying type.
+#[derive(Clone)]
+pub(crate) enum TypedefTarget {
+    /// A plain type name with no generic arguments and no pointer or
+    /// reference indirection, e.g. `using Foo = Bar;`.
+    NoArguments(TypeName),
+    /// Anything else - a templated type such as
+    /// `using Handle = std::unique_ptr<Foo>;`, or a typedef which adds a
+    /// layer of indirection such as `using Bytes = std::vector<uint8_t>*;`.
+    /// We keep the whole `syn::Type` so it can be fed back through the
+    /// normal type conversion pipeline.
+    General(Type),
+}
+
+pub(crate) fn analyze_typedef_target(ty: &Type) -> TypedefTarget {
+    match ty {
+        Type::Path(typ)
+            if typ
+                .path
+                .segments
+                .last()
+                .map(|s| s.arguments.is_empty())
+                .unwrap_or(true) =>
+        {
+            TypedefTarget::NoArguments(TypeName::from_type_path(typ))
+        }
+        _ => TypedefTarget::General(ty.clone()),
+    }
+}
@@ -13,11 +13,45 @@
 // limitations under the License.
 
 use itertools::Itertools;
-use syn::Type;
+use syn::{Ident, Type};
 
 use crate::types::TypeName;
 use std::collections::{HashMap, HashSet};
 
+/// A single virtual method discovered on a polymorphic C++ class, as
+/// needed both to generate a Rust trait mirroring it and to generate the
+/// C++ thunk which dispatches a virtual call back into whatever Rust
+/// type implements that trait.
+#[derive(Clone)]
+pub(crate) struct VirtualMethod {
+    pub(crate) name: Ident,
+    pub(crate) params: Vec<(Ident, Type)>,
+    pub(crate) return_type: Option<Type>,
+    pub(crate) is_pure: bool,
+    /// Whether the C++ declaration was `virtual ... Foo(...) const`. The
+    /// generated C++ override must repeat this qualifier, or it simply
+    /// won't override the base class's virtual method at all.
+    pub(crate) is_const: bool,
+}
+
+impl VirtualMethod {
+    pub(crate) fn new(
+        name: Ident,
+        params: Vec<(Ident, Type)>,
+        return_type: Option<Type>,
+        is_pure: bool,
+        is_const: bool,
+    ) -> Self {
+        Self {
+            name,
+            params,
+            return_type,
+            is_pure,
+            is_const,
+        }
+    }
+}
+
 /// Central registry of all information known about types.
 /// At present this is very minimal; in future we should roll
 /// known_types.rs into this and possibly other things as well.
@@ -26,6 +60,11 @@ pub(crate) struct TypeDatabase {
     nested_types: HashMap<TypeName, TypeName>,
     pod_requests: HashSet<TypeName>,
     allowlist: HashSet<String>, // not TypeName as it may be funcs not types.
+    // Classes found to have at least one virtual method, and the virtual
+    // methods we found on them, in declaration order (i.e. vtable order).
+    // Presence as a key here is what makes a class "polymorphic" as far
+    // as trait-impl generation is concerned.
+    virtual_methods: HashMap<TypeName, Vec<VirtualMethod>>,
 }
 
 impl TypeDatabase {
@@ -65,6 +104,42 @@ impl TypeDatabase {
         self.allowlist.contains(&tn.to_cpp_name())
     }
 
+    /// Record that `owner` (a C++ class) has the given virtual method.
+    /// Call this once per virtual method, in declaration order, while
+    /// walking the class's AST; the order is significant as it determines
+    /// the order in which we mirror the methods in the generated trait.
+    pub(crate) fn note_virtual_method(&mut self, owner: TypeName, method: VirtualMethod) {
+        self.virtual_methods.entry(owner).or_default().push(method);
+    }
+
+    /// Whether `tn` is a polymorphic class, i.e. has at least one virtual
+    /// method, and so is eligible to have a Rust trait generated for it.
+    pub(crate) fn is_polymorphic(&self, tn: &TypeName) -> bool {
+        self.virtual_methods.contains_key(tn)
+    }
+
+    pub(crate) fn virtual_methods_of(&self, tn: &TypeName) -> &[VirtualMethod] {
+        self.virtual_methods
+            .get(tn)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// All classes we've discovered to be polymorphic, i.e. every type for
+    /// which trait-impl glue should be generated, in a deterministic order.
+    ///
+    /// `virtual_methods` is a HashMap, so its key order is unspecified; we
+    /// sort by the C++ name here so that repeated runs over the same input
+    /// generate classes (and so thunk/trait glue) in the same order every
+    /// time, matching the care taken elsewhere in this series (methods
+    /// within a class are kept in vtable order, and `OverloadRegistry` is
+    /// insertion-ordered).
+    pub(crate) fn polymorphic_types(&self) -> impl Iterator<Item = &TypeName> {
+        self.virtual_methods
+            .keys()
+            .sorted_by_key(|tn| tn.to_cpp_name())
+    }
+
     pub(crate) fn type_to_cpp(&self, ty: &Type) -> String {
         match ty {
             Type::Path(typ) => {
@@ -114,3 +189,90 @@ impl TypeDatabase {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn tn(s: &str) -> TypeName {
+        TypeName::from_type_path(&syn::parse_str::<syn::TypePath>(s).unwrap())
+    }
+
+    #[test]
+    fn test_note_virtual_method_makes_type_polymorphic() {
+        let mut db = TypeDatabase::new();
+        let shape = tn("root::A::Shape");
+        assert!(!db.is_polymorphic(&shape));
+        assert!(db.virtual_methods_of(&shape).is_empty());
+
+        db.note_virtual_method(
+            shape.clone(),
+            VirtualMethod::new(
+                parse_quote! { area },
+                Vec::new(),
+                Some(parse_quote! { f64 }),
+                true,
+                true,
+            ),
+        );
+        db.note_virtual_method(
+            shape.clone(),
+            VirtualMethod::new(
+                parse_quote! { perimeter },
+                Vec::new(),
+                Some(parse_quote! { f64 }),
+                true,
+                false,
+            ),
+        );
+
+        assert!(db.is_polymorphic(&shape));
+        let methods = db.virtual_methods_of(&shape);
+        assert_eq!(methods.len(), 2);
+        // Declaration order must be preserved: it's vtable order.
+        assert_eq!(methods[0].name, "area");
+        assert_eq!(methods[1].name, "perimeter");
+        assert_eq!(db.polymorphic_types().collect::<Vec<_>>(), vec![&shape]);
+    }
+
+    #[test]
+    fn test_polymorphic_types_are_sorted_by_cpp_name() {
+        let mut db = TypeDatabase::new();
+        // Register in an order that doesn't already happen to be sorted,
+        // so a regression back to HashMap iteration order would likely
+        // (though not deterministically) be caught.
+        for name in ["root::A::Zebra", "root::A::Apple", "root::A::Mango"] {
+            db.note_virtual_method(
+                tn(name),
+                VirtualMethod::new(
+                    parse_quote! { area },
+                    Vec::new(),
+                    Some(parse_quote! { f64 }),
+                    true,
+                    true,
+                ),
+            );
+        }
+        assert_eq!(
+            db.polymorphic_types()
+                .map(TypeName::to_cpp_name)
+                .collect::<Vec<_>>(),
+            vec!["A::Apple", "A::Mango", "A::Zebra"]
+        );
+    }
+
+    #[test]
+    fn test_type_to_cpp_renders_reference_and_namespaced_types() {
+        let db = TypeDatabase::new();
+        assert_eq!(db.type_to_cpp(&parse_quote! { root::A::Foo }), "A::Foo");
+        assert_eq!(
+            db.type_to_cpp(&parse_quote! { & root::A::Foo }),
+            "const A::Foo&"
+        );
+        assert_eq!(
+            db.type_to_cpp(&parse_quote! { & mut root::A::Foo }),
+            "A::Foo&"
+        );
+    }
+}